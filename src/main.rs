@@ -1,52 +1,101 @@
 mod score;
 
+use std::io::{self, BufWriter};
+use std::path::Path;
+
 const LIMIT: u32 = 10;
 
 fn main() {
     let args = match get_args() {
         Ok(args) => args,
-        Err(error) => {
-            eprintln!("{}", error);
-            return;
-        }
+        Err(error) => fail(error),
     };
 
-    let mut scores = match score::get_scores(args.score_path) {
-        Ok(scores) => scores,
-        Err(error) => {
-            eprintln!("{}", error);
-            return;
+    let (mut scores, players) = match args {
+        Args::TarGzBundle { bundle_path } => match score::get_players_and_scores_from_tar_gz(&bundle_path) {
+            Ok((players, scores)) => (scores, players),
+            Err(error) => fail(error),
+        },
+        Args::Separate { score_path, player_path } => {
+            let scores = match load_scores(&score_path) {
+                Ok(scores) => scores,
+                Err(error) => fail(error),
+            };
+
+            let players = match load_players(&player_path) {
+                Ok(players) => players,
+                Err(error) => fail(error),
+            };
+
+            (scores, players)
         }
     };
 
-    let players = match score::get_players(args.player_path) {
-        Ok(players) => players,
-        Err(error) => {
-            eprintln!("{}", error);
-            return;
-        }
+    let ranking_scores = if (LIMIT as usize) < scores.len() {
+        score::top_k(&scores, LIMIT as usize)
+    } else {
+        score::sort(&mut scores);
+        score::rank(&scores, LIMIT)
     };
 
-    score::sort(&mut scores);
-    let ranking_scores = score::rank(&scores, LIMIT);
-    score::output(&ranking_scores, &players);
+    let stdout = io::stdout();
+    let mut writer = BufWriter::new(stdout.lock());
+    if let Err(error) = score::output(&mut writer, &ranking_scores, &players) {
+        fail(error);
+    }
 }
 
-struct Args {
-    score_path: String,
-    player_path: String,
+/// エラーメッセージを標準エラー出力に書き込み、エラーの種類に応じた
+/// 終了コードでプロセスを終了します。
+fn fail(error: score::ScoreError) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(error.exit_code());
 }
 
-fn get_args() -> Result<Args, String> {
+enum Args {
+    /// スコアファイルとプレイヤーファイルをそれぞれのパス (または `-` で標準入力) から読み込みます。
+    Separate { score_path: String, player_path: String },
+    /// `players.csv` / `scores.csv` を含む tar.gz バンドルから両方まとめて読み込みます。
+    TarGzBundle { bundle_path: String },
+}
+
+fn get_args() -> Result<Args, score::ScoreError> {
     use std::env;
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
-        return Err("引数に誤りがあります".to_string());
+    match args.len() {
+        2 if args[1].ends_with(".tar.gz") => Ok(Args::TarGzBundle {
+            bundle_path: args[1].clone(),
+        }),
+        3 if args[1] == "-" && args[2] == "-" => Err(score::ScoreError::InvalidArgs(
+            "標準入力 (-) はスコアファイル・プレイヤーファイルのどちらか一方にしか指定できません".to_string(),
+        )),
+        3 => Ok(Args::Separate {
+            score_path: args[1].clone(),
+            player_path: args[2].clone(),
+        }),
+        _ => Err(score::ScoreError::InvalidArgs("引数に誤りがあります".to_string())),
+    }
+}
+
+///
+/// スコア情報を取得します。`path` が `-` のときは標準入力から読み込みます。
+///
+fn load_scores(path: &str) -> Result<Vec<score::Score>, score::ScoreError> {
+    if path == "-" {
+        score::parse_scores(io::stdin().lock(), Path::new("<stdin>"))
+    } else {
+        score::get_scores(path)
     }
+}
 
-    Ok(Args {
-        score_path: args[1].clone(),
-        player_path: args[2].clone(),
-    })
+///
+/// プレイヤー情報を取得します。`path` が `-` のときは標準入力から読み込みます。
+///
+fn load_players(path: &str) -> Result<std::collections::HashMap<String, score::Player>, score::ScoreError> {
+    if path == "-" {
+        score::parse_players(io::stdin().lock(), Path::new("<stdin>"))
+    } else {
+        score::get_players(path)
+    }
 }
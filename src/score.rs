@@ -1,21 +1,124 @@
 use std::{
     cmp::Ordering,
-    collections::HashMap,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fmt,
     fs::File,
-    io::{BufRead, BufReader},
-    path::Path,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
 };
 
+use flate2::read::GzDecoder;
+use tar::Archive;
+
+/// スコア・プレイヤーファイルの読み込みや解析で発生するエラーです。
+#[derive(Debug)]
+pub enum ScoreError {
+    /// ファイルのオープンに失敗しました。
+    OpenFailed(PathBuf),
+    /// 行のフィールド数が想定と異なります。
+    MalformedRow {
+        path: PathBuf,
+        line_no: usize,
+        found_fields: usize,
+        expected: usize,
+    },
+    /// スコアの値が数値として解析できません。
+    InvalidScore {
+        path: PathBuf,
+        line_no: usize,
+        raw: String,
+    },
+    /// ファイルの読み取りに失敗しました。
+    ReadFailed(io::Error),
+    /// 出力の書き込みに失敗しました。
+    WriteFailed(io::Error),
+    /// コマンドライン引数が不正です。
+    InvalidArgs(String),
+    /// tar.gz バンドルに必須のエントリが含まれていません。
+    MissingEntry { path: PathBuf, entry_name: &'static str },
+    /// スコアが参照しているプレイヤーIDがプレイヤーファイルに存在しません。
+    UnknownPlayer(String),
+}
+
+impl fmt::Display for ScoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScoreError::OpenFailed(path) => {
+                write!(f, "ファイルのオープンに失敗しました: {}", path.display())
+            }
+            ScoreError::MalformedRow {
+                path,
+                line_no,
+                found_fields,
+                expected,
+            } => write!(
+                f,
+                "{}:{}: フォーマットが不正です (フィールド数 {}, 期待値 {})",
+                path.display(),
+                line_no,
+                found_fields,
+                expected
+            ),
+            ScoreError::InvalidScore { path, line_no, raw } => write!(
+                f,
+                "{}:{}: スコアの値が不正です: {}",
+                path.display(),
+                line_no,
+                raw
+            ),
+            ScoreError::ReadFailed(error) => write!(f, "ファイルの読み取りに失敗しました: {}", error),
+            ScoreError::WriteFailed(error) => write!(f, "出力の書き込みに失敗しました: {}", error),
+            ScoreError::InvalidArgs(message) => write!(f, "{}", message),
+            ScoreError::MissingEntry { path, entry_name } => write!(
+                f,
+                "{}: 必須のエントリ {} が見つかりません",
+                path.display(),
+                entry_name
+            ),
+            ScoreError::UnknownPlayer(player_id) => write!(
+                f,
+                "スコアが参照しているプレイヤーID {} がプレイヤーファイルに存在しません",
+                player_id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ScoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScoreError::ReadFailed(error) => Some(error),
+            ScoreError::WriteFailed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl ScoreError {
+    /// エラーの種類に応じた終了コードを返します。パイプライン上で
+    /// エラーの原因を区別できるよう、種類ごとに異なる値を割り当てます。
+    /// ただし `MalformedRow` と `InvalidScore` はどちらも入力フォーマットのエラーとして同じ値 4 を返します。
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ScoreError::InvalidArgs(_) => 2,
+            ScoreError::OpenFailed(_) => 3,
+            ScoreError::MalformedRow { .. } | ScoreError::InvalidScore { .. } => 4,
+            ScoreError::MissingEntry { .. } => 5,
+            ScoreError::UnknownPlayer(_) => 6,
+            ScoreError::ReadFailed(_) => 7,
+            ScoreError::WriteFailed(_) => 8,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Player {
-    player_id: String,
     handle_name: String,
 }
 
 impl Player {
-    fn new<T: Into<String>>(player_id: T, handle_name: T) -> Self {
+    fn new<T: Into<String>>(handle_name: T) -> Self {
         Self {
-            player_id: player_id.into(),
             handle_name: handle_name.into(),
         }
     }
@@ -58,24 +161,53 @@ pub struct RankingScore<'a> {
 }
 
 ///
-/// プレイヤーファイルからプレイヤーを取得します。
+/// ファイルを開き、`.gz` 拡張子であればストリーミングで展開しながら読み込みます。
 ///
-/// player_path - プレイヤーファイル
+/// path - 読み込むファイル
 ///
-pub fn get_players<P: AsRef<Path>>(player_path: P) -> Result<HashMap<String, Player>, String> {
-    let file = match File::open(player_path) {
+fn open_reader<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>, ScoreError> {
+    let path = path.as_ref().to_path_buf();
+    let file = match File::open(&path) {
         Ok(file) => file,
         Err(_) => {
-            return Err("プレイヤーファイルのオープンに失敗しました".to_string());
+            return Err(ScoreError::OpenFailed(path));
         }
     };
 
-    let mut reader = BufReader::new(file);
+    if path.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+///
+/// プレイヤーファイルからプレイヤーを取得します。`.csv.gz` のように圧縮されたファイルにも対応します。
+///
+/// player_path - プレイヤーファイル
+///
+pub fn get_players<P: AsRef<Path>>(player_path: P) -> Result<HashMap<String, Player>, ScoreError> {
+    let path = player_path.as_ref().to_path_buf();
+    let reader = open_reader(&path)?;
+    parse_players(reader, &path)
+}
+
+///
+/// 任意の `BufRead` からプレイヤーを解析します。ファイルパスだけでなく標準入力やテスト用の
+/// 文字列リテラルなど、行指向で読み出せるものであれば何でも渡せます。
+///
+/// reader - プレイヤー情報を読み出す入力
+///
+/// path - エラーメッセージに表示するファイル名 (標準入力の場合は `<stdin>` など)
+///
+pub fn parse_players<R: BufRead>(mut reader: R, path: &Path) -> Result<HashMap<String, Player>, ScoreError> {
     let _ = reader.read_line(&mut String::new());
     let mut players: HashMap<String, Player> = HashMap::new();
+    let mut line_no = 1;
 
     loop {
         let mut line = String::new();
+        line_no += 1;
 
         match reader.read_line(&mut line) {
             Ok(0) => break,
@@ -83,16 +215,21 @@ pub fn get_players<P: AsRef<Path>>(player_path: P) -> Result<HashMap<String, Pla
                 let fields: Vec<&str> = line.lines().collect::<Vec<&str>>()[0].split(",").collect();
 
                 if fields.len() != 2 {
-                    return Err("プレイヤーファイルのフォーマットが不正です".to_string());
+                    return Err(ScoreError::MalformedRow {
+                        path: path.to_path_buf(),
+                        line_no,
+                        found_fields: fields.len(),
+                        expected: 2,
+                    });
                 }
 
                 let player_id = fields[0].to_string();
                 let handle_name = fields[1].to_string();
 
-                players.insert(player_id.clone(), Player::new(player_id, handle_name));
+                players.insert(player_id, Player::new(handle_name));
             }
-            Err(_) => {
-                return Err("スコアファイルの読み取りに失敗しました".to_string());
+            Err(error) => {
+                return Err(ScoreError::ReadFailed(error));
             }
         }
     }
@@ -101,24 +238,32 @@ pub fn get_players<P: AsRef<Path>>(player_path: P) -> Result<HashMap<String, Pla
 }
 
 ///
-/// スコアファイルからプレイヤー単位毎のスコア情報を取得します。
+/// スコアファイルからプレイヤー単位毎のスコア情報を取得します。`.csv.gz` のように圧縮されたファイルにも対応します。
 ///
 /// score_path - スコアファイル
 ///
-pub fn get_scores<P: AsRef<Path>>(score_path: P) -> Result<Vec<Score>, String> {
-    let file = match File::open(score_path) {
-        Ok(file) => file,
-        Err(_) => {
-            return Err("スコアファイルのオープンに失敗しました".to_string());
-        }
-    };
+pub fn get_scores<P: AsRef<Path>>(score_path: P) -> Result<Vec<Score>, ScoreError> {
+    let path = score_path.as_ref().to_path_buf();
+    let reader = open_reader(&path)?;
+    parse_scores(reader, &path)
+}
 
-    let mut reader = BufReader::new(file);
+///
+/// 任意の `BufRead` からプレイヤー単位毎のスコア情報を解析します。ファイルパスだけでなく標準入力や
+/// テスト用の文字列リテラルなど、行指向で読み出せるものであれば何でも渡せます。
+///
+/// reader - スコア情報を読み出す入力
+///
+/// path - エラーメッセージに表示するファイル名 (標準入力の場合は `<stdin>` など)
+///
+pub fn parse_scores<R: BufRead>(mut reader: R, path: &Path) -> Result<Vec<Score>, ScoreError> {
     let _ = reader.read_line(&mut String::new());
     let mut scores: HashMap<String, Score> = HashMap::new();
+    let mut line_no = 1;
 
     loop {
         let mut line = String::new();
+        line_no += 1;
 
         match reader.read_line(&mut line) {
             Ok(0) => break,
@@ -126,12 +271,21 @@ pub fn get_scores<P: AsRef<Path>>(score_path: P) -> Result<Vec<Score>, String> {
                 let fields: Vec<&str> = line.lines().collect::<Vec<&str>>()[0].split(",").collect();
 
                 if fields.len() != 3 {
-                    return Err("スコアファイルのフォーマットが不正です".to_string());
+                    return Err(ScoreError::MalformedRow {
+                        path: path.to_path_buf(),
+                        line_no,
+                        found_fields: fields.len(),
+                        expected: 3,
+                    });
                 }
 
                 let player_id = fields[1].to_string();
                 let Ok(score) = fields[2].parse::<f64>() else {
-                    return Err("スコアがフォーマットが不正です".to_string());
+                    return Err(ScoreError::InvalidScore {
+                        path: path.to_path_buf(),
+                        line_no,
+                        raw: fields[2].to_string(),
+                    });
                 };
 
                 match scores.get_mut(&player_id) {
@@ -141,8 +295,8 @@ pub fn get_scores<P: AsRef<Path>>(score_path: P) -> Result<Vec<Score>, String> {
                     }
                 }
             }
-            Err(_) => {
-                return Err("スコアファイルの読み取りに失敗しました".to_string());
+            Err(error) => {
+                return Err(ScoreError::ReadFailed(error));
             }
         }
     }
@@ -154,27 +308,117 @@ pub fn get_scores<P: AsRef<Path>>(score_path: P) -> Result<Vec<Score>, String> {
     Ok(scores.into_values().collect())
 }
 
+#[cfg(test)]
+mod parse_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_players_from_a_str_reader() {
+        let input = "player_id,handle_name\np1,Alice\np2,Bob\n";
+        let players = parse_players(Cursor::new(input), Path::new("<test>")).unwrap();
+
+        assert_eq!(players.len(), 2);
+        assert_eq!(players["p1"].handle_name, "Alice");
+        assert_eq!(players["p2"].handle_name, "Bob");
+    }
+
+    #[test]
+    fn parses_scores_and_averages_per_player() {
+        let input = "match_id,player_id,score\nm1,p1,10\nm2,p1,20\nm3,p2,5\n";
+        let scores = parse_scores(Cursor::new(input), Path::new("<test>")).unwrap();
+
+        let p1 = scores.iter().find(|s| s.player_id == "p1").unwrap();
+        let p2 = scores.iter().find(|s| s.player_id == "p2").unwrap();
+
+        assert_eq!(p1.mean_score, 15.0);
+        assert_eq!(p2.mean_score, 5.0);
+    }
+}
+
+///
+/// `scores.tar.gz` のような tar+gzip バンドルから、`players.csv` と `scores.csv` という名前の
+/// エントリを探してプレイヤーとスコア情報をまとめて取得します。エントリはアーカイブ内をストリーミングで
+/// 読み進めながら解析するため、展開後のデータを丸ごとメモリに載せることはありません。
+///
+/// bundle_path - `players.csv` / `scores.csv` を含む tar.gz バンドル
+///
+pub fn get_players_and_scores_from_tar_gz<P: AsRef<Path>>(
+    bundle_path: P,
+) -> Result<(HashMap<String, Player>, Vec<Score>), ScoreError> {
+    let path = bundle_path.as_ref().to_path_buf();
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(_) => {
+            return Err(ScoreError::OpenFailed(path));
+        }
+    };
+
+    let mut archive = Archive::new(GzDecoder::new(BufReader::new(file)));
+    let mut players: HashMap<String, Player> = HashMap::new();
+    let mut scores: Vec<Score> = Vec::new();
+    let mut has_players = false;
+    let mut has_scores = false;
+
+    let entries = archive.entries().map_err(ScoreError::ReadFailed)?;
+    for entry in entries {
+        let entry = entry.map_err(ScoreError::ReadFailed)?;
+        let entry_path = entry.path().map_err(ScoreError::ReadFailed)?.to_path_buf();
+
+        match entry_path.file_name().and_then(|name| name.to_str()) {
+            Some("players.csv") => {
+                players = parse_players(BufReader::new(entry), &path)?;
+                has_players = true;
+            }
+            Some("scores.csv") => {
+                scores = parse_scores(BufReader::new(entry), &path)?;
+                has_scores = true;
+            }
+            _ => {}
+        }
+    }
+
+    if !has_players {
+        return Err(ScoreError::MissingEntry {
+            path,
+            entry_name: "players.csv",
+        });
+    }
+
+    if !has_scores {
+        return Err(ScoreError::MissingEntry {
+            path,
+            entry_name: "scores.csv",
+        });
+    }
+
+    Ok((players, scores))
+}
+
 ///
 /// スコア情報を並び返します。
 ///
 /// scores - スコア情報
 ///
-pub fn sort(scores: &mut Vec<Score>) {
-    scores.sort_by(|a, b| {
-        if a.mean_score == b.mean_score {
-            if a.player_id > b.player_id {
-                return Ordering::Greater;
-            } else {
-                return Ordering::Less;
-            };
-        }
+pub fn sort(scores: &mut [Score]) {
+    scores.sort_by(score_order);
+}
 
-        if a.mean_score < b.mean_score {
-            Ordering::Greater
+/// `sort`/`top_k` で共通して使う並び順です。平均スコア降順、同点はプレイヤーID昇順とします。
+fn score_order(a: &Score, b: &Score) -> Ordering {
+    if a.mean_score == b.mean_score {
+        if a.player_id > b.player_id {
+            return Ordering::Greater;
         } else {
-            Ordering::Less
-        }
-    });
+            return Ordering::Less;
+        };
+    }
+
+    if a.mean_score < b.mean_score {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    }
 }
 
 ///
@@ -184,7 +428,7 @@ pub fn sort(scores: &mut Vec<Score>) {
 ///
 /// limit - 順位を付与する上限
 ///
-pub fn rank(scores: &Vec<Score>, limit: u32) -> Vec<RankingScore> {
+pub fn rank(scores: &[Score], limit: u32) -> Vec<RankingScore<'_>> {
     let mut rank = 1;
     let mut index = 0;
     let mut ranking_scores = Vec::<RankingScore>::new();
@@ -215,22 +459,157 @@ pub fn rank(scores: &Vec<Score>, limit: u32) -> Vec<RankingScore> {
     ranking_scores
 }
 
+/// 有界ヒープの中で最も順位の低い平均スコアを根に持たせるためのラッパーです。
+/// 平均スコアの値そのものを保持し、プレイヤー単位ではなく同点グループ単位で比較します。
+#[derive(PartialEq)]
+struct MeanScore(f64);
+
+impl Eq for MeanScore {}
+
+impl PartialOrd for MeanScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MeanScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.0 == other.0 {
+            Ordering::Equal
+        } else if self.0 < other.0 {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        }
+    }
+}
+
+///
+/// 全件を `sort` してから上位を取り出す代わりに、容量 limit の有界ヒープで上位 limit 件分の
+/// 平均スコアだけを選び出して順位を付与します。`rank` と同じく同点のプレイヤーは全員含めるため、
+/// ヒープは平均スコアの値 (同点グループ) 単位で管理し、プレイヤー数そのものは制限しません。
+/// 母数が多く上位の同点グループだけが必要な場合、全件ソートの O(n log n) より高速です。
+///
+/// scores - スコア情報
+///
+/// limit - 対象とする同点グループ数の上限 (ヒープの容量でもあります)
+///
+pub fn top_k(scores: &[Score], limit: usize) -> Vec<RankingScore<'_>> {
+    if limit == 0 || scores.is_empty() {
+        return Vec::new();
+    }
+
+    let mut heap: BinaryHeap<MeanScore> = BinaryHeap::with_capacity(limit + 1);
+    let mut seen = HashSet::new();
+
+    for score in scores {
+        // `-0.0` と `0.0` は `rank` では同点なので、`+ 0.0` で `0.0` に揃えてから重複を判定します。
+        if seen.insert((score.mean_score + 0.0).to_bits()) {
+            heap.push(MeanScore(score.mean_score));
+
+            if heap.len() > limit {
+                heap.pop();
+            }
+        }
+    }
+
+    let threshold = match heap.peek() {
+        Some(worst) => worst.0,
+        None => return Vec::new(),
+    };
+
+    let mut top: Vec<&Score> = scores.iter().filter(|score| score.mean_score >= threshold).collect();
+    top.sort_by(|a, b| score_order(a, b));
+
+    let mut rank = 1;
+    let mut index = 0;
+    let mut ranking_scores = Vec::<RankingScore>::new();
+
+    while top.len() > index {
+        ranking_scores.push(RankingScore {
+            rank,
+            inner: top[index],
+        });
+
+        let next_index = index + 1;
+
+        if top.len() > next_index {
+            rank = if top[index].mean_score == top[next_index].mean_score {
+                rank
+            } else {
+                rank + 1
+            };
+        }
+
+        index += 1;
+    }
+
+    ranking_scores
+}
+
 ///
 /// ランキングスコアを出力します。
 ///
+/// w - 出力先
+///
 /// ranking_scores - ランキングスコア
 ///
 /// players - プレイヤー情報
 ///
-pub fn output(ranking_scores: &Vec<RankingScore>, players: &HashMap<String, Player>) {
-    println!("rank,player_id,handle_name,mean_score");
+pub fn output<W: io::Write>(
+    w: &mut W,
+    ranking_scores: &Vec<RankingScore>,
+    players: &HashMap<String, Player>,
+) -> Result<(), ScoreError> {
+    writeln!(w, "rank,player_id,handle_name,mean_score").map_err(ScoreError::WriteFailed)?;
 
     for score in ranking_scores.iter() {
-        let player = players.get(&score.inner.player_id).unwrap();
+        let player = players
+            .get(&score.inner.player_id)
+            .ok_or_else(|| ScoreError::UnknownPlayer(score.inner.player_id.clone()))?;
 
-        println!(
+        writeln!(
+            w,
             "{},{},{},{}",
             score.rank, score.inner.player_id, player.handle_name, score.inner.mean_score
+        )
+        .map_err(ScoreError::WriteFailed)?;
+    }
+
+    w.flush().map_err(ScoreError::WriteFailed)
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+
+    #[test]
+    fn writes_ranking_rows_into_a_vec_u8() {
+        let mut players = HashMap::new();
+        players.insert("p1".to_string(), Player::new("Alice"));
+
+        let mut score = Score::new("p1", 30.0);
+        score.average();
+        let ranking_scores = vec![RankingScore { rank: 1, inner: &score }];
+
+        let mut buf: Vec<u8> = Vec::new();
+        output(&mut buf, &ranking_scores, &players).unwrap();
+
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "rank,player_id,handle_name,mean_score\n1,p1,Alice,30\n"
         );
     }
+
+    #[test]
+    fn errors_on_unknown_player() {
+        let players = HashMap::new();
+        let score = Score::new("p1", 30.0);
+        let ranking_scores = vec![RankingScore { rank: 1, inner: &score }];
+
+        let mut buf: Vec<u8> = Vec::new();
+        let error = output(&mut buf, &ranking_scores, &players).unwrap_err();
+
+        assert!(matches!(error, ScoreError::UnknownPlayer(player_id) if player_id == "p1"));
+    }
 }
\ No newline at end of file